@@ -1,11 +1,23 @@
 extern crate argonautica;
+extern crate base64;
+extern crate data_encoding;
 extern crate etcd;
 extern crate fruently;
 extern crate futures;
+extern crate hmac;
 extern crate hyper;
 extern crate iron;
+extern crate jsonwebtoken;
 extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate router;
+extern crate sha1;
+extern crate sha2;
+extern crate sodiumoxide;
+extern crate subtle;
 extern crate tokio_core;
 extern crate uuid;
 
@@ -20,13 +32,26 @@ use etcd::kv;
 use fruently::fluent::Fluent;
 use fruently::forwardable::JsonForwardable;
 use futures::Future;
+use hmac::{Hmac, Mac};
 use iron::headers::*;
+use iron::middleware::BeforeMiddleware;
 use iron::prelude::*;
+use iron::typemap::Key;
+use iron::{Chain, Handler};
+use jsonwebtoken::{decode, encode, Algorithm, Header, Validation};
 use prometheus::{Counter, Encoder, TextEncoder};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use router::Router;
+use sha1::Sha1;
+use sha2::Sha256;
+use sodiumoxide::crypto::secretbox;
+use std::collections::HashMap;
 use std::convert::From;
+use std::io::Read;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
 use tokio_core::reactor::Core;
 
 error_chain! {
@@ -57,6 +82,51 @@ lazy_static! {
             600
         }
     };
+    // When set, requests are not authenticated and a fixed anonymous subject is
+    // injected instead, for local/dev use.
+    static ref OPEN_AUTH: bool = {
+        std::env::var("OPEN_AUTH")
+            .map(|val| val == "1" || val.eq_ignore_ascii_case("true"))
+            .unwrap_or(false)
+    };
+    // Signed JWTs verify locally, so the per-request revocation lookup in etcd
+    // is opt-in: enable it only when explicit logout/revocation is required,
+    // otherwise an authorized request costs no etcd round trip.
+    static ref TOKEN_REVOCATION_ENABLED: bool = {
+        std::env::var("TOKEN_REVOCATION_ENABLED")
+            .map(|val| val == "1" || val.eq_ignore_ascii_case("true"))
+            .unwrap_or(false)
+    };
+
+    // Master key used to seal secret values at rest. Read from MASTER_KEY as
+    // 32 base64-encoded bytes and decoded once at startup.
+    static ref MASTER_KEY: secretbox::Key = {
+        let encoded = std::env::var("MASTER_KEY")
+            .expect("MASTER_KEY environment variable must be set");
+        let raw = base64::decode(&encoded).expect("MASTER_KEY must be valid base64");
+        secretbox::Key::from_slice(&raw).expect("MASTER_KEY must decode to 32 bytes")
+    };
+
+    // Versioned master keys used to seal secrets at rest, seeded with the
+    // bootstrap MASTER_KEY at version 0. The active version seals new writes;
+    // older versions are retained so blobs written before a rotation stay
+    // openable while a rotation is in progress.
+    static ref KEY_RING: RwLock<KeyRing> = {
+        let mut keys = HashMap::new();
+        keys.insert(0u8, MASTER_KEY.clone());
+        RwLock::new(KeyRing { active: 0, keys })
+    };
+
+    // Secret used to sign and verify HS256 session JWTs.
+    static ref JWT_SECRET: String = {
+        std::env::var("JWT_SECRET").expect("JWT_SECRET environment variable must be set")
+    };
+
+    // Root HMAC key from which macaroon signatures are derived. Falls back to
+    // the JWT secret when not set explicitly.
+    static ref MACAROON_ROOT_KEY: String = {
+        std::env::var("MACAROON_ROOT_KEY").unwrap_or_else(|_| JWT_SECRET.clone())
+    };
 
     // Application instance SPIFFE ID
     static ref SPIFFE_ID: &'static str = "spiffe://example.org/simple-secrets1";
@@ -123,6 +193,14 @@ lazy_static! {
     };
 }
 
+// Versioned set of master keys. Each sealed blob records the version it was
+// sealed under in a leading byte, so the right key can be selected on open and
+// an interrupted rotation is recoverable from what is already on disk.
+struct KeyRing {
+    active: u8,
+    keys: HashMap<u8, secretbox::Key>,
+}
+
 fn telemetry_config_failed_panic(e: &prometheus::Error) -> prometheus::Counter {
     eprintln!("Unable to create prometheus primative {}", e);
     panic!("Error creating Prometheus telemetry primative");
@@ -136,13 +214,24 @@ enum ServerEvents {
     TokenCreated,
     LoginSuccess,
     SecretCreateFailure,
-    SecretCreateFailureNoToken,
     SecretCreateFailureInvalidToken,
     SecretCreateSuccess,
-    SecretFetchFailureNoToken,
     SecretFetchFailureInvalidToken,
     SecretFetchFailureNoExist,
     SecretFetchSuccess,
+    SecretDecryptFailure,
+    SecretAccessDenied,
+    TokenExpired,
+    TokenSignatureInvalid,
+    AuthorizationMissingToken,
+    AuthorizationInvalidToken,
+    LoginFailureInvalidTotp,
+    TotpEnrolled,
+    MacaroonMinted,
+    MacaroonCaveatFailed,
+    KeyRotationStart,
+    KeyRotationSecret,
+    KeyRotationComplete,
 }
 
 impl std::fmt::Display for ServerEvents {
@@ -156,13 +245,24 @@ impl std::fmt::Display for ServerEvents {
             ServerEvents::TokenCreated => "TOKEN_CREATED",
             ServerEvents::LoginSuccess => "LOGIN_SUCCESS",
             ServerEvents::SecretCreateFailure => "SECRET_CREATE_FAILURE",
-            ServerEvents::SecretCreateFailureNoToken => "SECRET_CREATE_FAILURE_NO_TOKEN",
             ServerEvents::SecretCreateFailureInvalidToken => "SECRET_CREATE_FAILURE_INVALID_TOKEN",
             ServerEvents::SecretCreateSuccess => "SECRET_CREATE_SUCCESS",
-            ServerEvents::SecretFetchFailureNoToken => "SECRET_FETCH_FAILURE_NO_TOKEN",
             ServerEvents::SecretFetchFailureInvalidToken => "SECRET_FETCH_FAILURE_INVALID_TOKEN",
             ServerEvents::SecretFetchFailureNoExist => "SECRET_FETCH_FAILURE_NOEXIST",
             ServerEvents::SecretFetchSuccess => "SECRET_FETCH_SUCCESS",
+            ServerEvents::SecretDecryptFailure => "SECRET_DECRYPT_FAILURE",
+            ServerEvents::SecretAccessDenied => "SECRET_ACCESS_DENIED",
+            ServerEvents::TokenExpired => "TOKEN_EXPIRED",
+            ServerEvents::TokenSignatureInvalid => "TOKEN_SIGNATURE_INVALID",
+            ServerEvents::AuthorizationMissingToken => "AUTHORIZATION_MISSING_TOKEN",
+            ServerEvents::AuthorizationInvalidToken => "AUTHORIZATION_INVALID_TOKEN",
+            ServerEvents::LoginFailureInvalidTotp => "LOGIN_FAILURE_INVALID_TOTP",
+            ServerEvents::TotpEnrolled => "TOTP_ENROLLED",
+            ServerEvents::MacaroonMinted => "MACAROON_MINTED",
+            ServerEvents::MacaroonCaveatFailed => "MACAROON_CAVEAT_FAILED",
+            ServerEvents::KeyRotationStart => "KEY_ROTATION_START",
+            ServerEvents::KeyRotationSecret => "KEY_ROTATION_SECRET",
+            ServerEvents::KeyRotationComplete => "KEY_ROTATION_COMPLETE",
         };
         write!(f, "{}", output)
     }
@@ -182,10 +282,21 @@ fn audit_event(event: ServerEvents, content: &str) {
 }
 
 fn main() {
+    sodiumoxide::init().expect("Unable to initialize libsodium");
+    lazy_static::initialize(&MASTER_KEY);
+    lazy_static::initialize(&JWT_SECRET);
+    restore_key_ring();
+
     let mut api_router = Router::new();
     api_router.get("/login", login, "login");
-    api_router.get("/get/:name", fetch_secret, "get_secret");
-    api_router.post("/set/:name/:value", set_secret, "set_secret");
+    api_router.get("/logout", logout, "logout");
+    api_router.post("/totp/enroll", totp_enroll, "totp_enroll");
+    api_router.get("/get/:name", protected(fetch_secret), "get_secret");
+    api_router.post("/set/:name/:value", protected(set_secret), "set_secret");
+    api_router.post("/roles/:name", protected(create_role), "create_role");
+    api_router.post("/users/:name/roles", protected(set_user_roles), "set_user_roles");
+    api_router.post("/token/attenuate", protected(attenuate_token), "attenuate_token");
+    api_router.post("/rotatekey", protected(rotate_key), "rotate_key");
     let _api = Iron::new(api_router).http("0.0.0.0:3000");
 
     let mut metrics_router = Router::new();
@@ -220,6 +331,202 @@ struct UserInfo {
     token: AuthToken,
 }
 
+// Claims carried by a signed session JWT. `scopes` gates which operations the
+// bearer may perform; `jti` keys the optional etcd revocation list.
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    exp: u64,
+    iat: u64,
+    jti: String,
+    scopes: Vec<String>,
+    // A macaroon `secret =` caveat restricting the token to a single secret;
+    // absent for ordinary session JWTs.
+    #[serde(default)]
+    secret: Option<String>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Typed authorization context inserted into `req.extensions` by the auth
+// middleware once a request's token has been validated, so handlers never
+// touch raw query strings.
+#[derive(Clone, Debug)]
+struct Authorization {
+    subject: String,
+    scopes: Vec<String>,
+    // When present (macaroon-derived), the token may only act on this secret.
+    secret: Option<String>,
+}
+
+impl Authorization {
+    fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+impl Key for Authorization {
+    type Value = Authorization;
+}
+
+// A single RBAC grant: read and/or write access to every secret whose path
+// begins with `prefix`. Roles stored at `/roles/{role}` are JSON arrays of
+// these, modeled on etcd's own key-prefix permission design.
+#[derive(Debug, Serialize, Deserialize)]
+struct Grant {
+    prefix: String,
+    #[serde(default)]
+    read: bool,
+    #[serde(default)]
+    write: bool,
+}
+
+// Resolve a subject's effective grants by loading its role bindings from
+// `/users/{subject}/roles` and expanding each role's grant list.
+fn effective_grants(subject: &str) -> Vec<Grant> {
+    let roles = match get_etcd_key(&format!("/users/{}/roles", subject)) {
+        Ok(raw) if !raw.is_empty() => {
+            serde_json::from_str::<Vec<String>>(&raw).unwrap_or_default()
+        }
+        _ => Vec::new(),
+    };
+
+    let mut grants = Vec::new();
+    for role in roles {
+        if let Ok(raw) = get_etcd_key(&format!("/roles/{}", role)) {
+            if let Ok(mut role_grants) = serde_json::from_str::<Vec<Grant>>(&raw) {
+                grants.append(&mut role_grants);
+            }
+        }
+    }
+    grants
+}
+
+// Authorize an operation against a secret's path. Fails closed: any missing
+// binding, malformed role, or etcd error results in denial.
+fn authorize_secret(subject: &str, resource: &str, write: bool) -> bool {
+    effective_grants(subject).iter().any(|grant| {
+        resource.starts_with(&grant.prefix) && if write { grant.write } else { grant.read }
+    })
+}
+
+// An administrator is any user bound to the reserved `admin` role. Admins are
+// granted the `admin` scope at login so the role-management and key-rotation
+// endpoints are reachable without attenuating a macaroon.
+fn is_admin(subject: &str) -> bool {
+    match get_etcd_key(&format!("/users/{}/roles", subject)) {
+        Ok(ref raw) if !raw.is_empty() => serde_json::from_str::<Vec<String>>(raw)
+            .map(|roles| roles.iter().any(|role| role == "admin"))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+// Pull the bearer token from the `Authorization: Bearer` header, falling back
+// to a `token=` query parameter. The query is parsed field-by-field so a
+// multi-parameter query string is handled correctly.
+fn extract_token(req: &Request) -> Option<String> {
+    if let Some(&iron::headers::Authorization(ref bearer)) =
+        req.headers.get::<iron::headers::Authorization<Bearer>>()
+    {
+        return Some(bearer.token.clone());
+    }
+
+    req.url.query().and_then(|query| {
+        query.split('&').find_map(|pair| {
+            let mut parts = pair.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("token"), Some(value)) => Some(value.to_string()),
+                _ => None,
+            }
+        })
+    })
+}
+
+// BeforeMiddleware that validates the request token once and publishes an
+// `Authorization` context. All token-level auth failures are audited here.
+struct AuthMiddleware;
+
+impl BeforeMiddleware for AuthMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let token = match extract_token(req) {
+            Some(token) => token,
+            None => {
+                audit_event(
+                    ServerEvents::AuthorizationMissingToken,
+                    "Request rejected, no token presented",
+                );
+                return Err(IronError::new(
+                    Error::from("Token required"),
+                    iron::status::Unauthorized,
+                ));
+            }
+        };
+
+        match validate_token(&token) {
+            Ok(claims) => {
+                req.extensions.insert::<Authorization>(Authorization {
+                    subject: claims.sub,
+                    scopes: claims.scopes,
+                    secret: claims.secret,
+                });
+                Ok(())
+            }
+            Err(_) => {
+                audit_event(
+                    ServerEvents::AuthorizationInvalidToken,
+                    "Request rejected, invalid token presented",
+                );
+                Err(IronError::new(
+                    Error::from("Bad token"),
+                    iron::status::Unauthorized,
+                ))
+            }
+        }
+    }
+}
+
+// Open variant for local/dev use: injects a fixed anonymous subject with the
+// read and write scopes (not `admin`) and performs no token validation.
+struct OpenAuthMiddleware {
+    subject: String,
+}
+
+impl OpenAuthMiddleware {
+    fn new() -> Self {
+        OpenAuthMiddleware {
+            subject: String::from("anonymous"),
+        }
+    }
+}
+
+impl BeforeMiddleware for OpenAuthMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        req.extensions.insert::<Authorization>(Authorization {
+            subject: self.subject.clone(),
+            scopes: vec![String::from("read"), String::from("write")],
+            secret: None,
+        });
+        Ok(())
+    }
+}
+
+// Wrap a handler in a chain guarded by whichever auth middleware is configured.
+fn protected<H: Handler>(handler: H) -> Chain {
+    let mut chain = Chain::new(handler);
+    if *OPEN_AUTH {
+        chain.link_before(OpenAuthMiddleware::new());
+    } else {
+        chain.link_before(AuthMiddleware);
+    }
+    chain
+}
+
 fn fetch_user_password(user_info: &mut UserInfo) {
     if let Ok(value) = get_etcd_key(&format!("/users/{}/password", user_info.username)) {
         user_info.encoded_password = value
@@ -241,7 +548,7 @@ fn verify_password(user_info: &UserInfo) -> bool {
 
 fn login(req: &mut Request) -> IronResult<Response> {
     // Parse username and password from request
-    let auth = match req.headers.get::<Authorization<Basic>>() {
+    let auth = match req.headers.get::<iron::headers::Authorization<Basic>>() {
         Some(auth) => auth,
         None => return Ok(Response::with(iron::status::Unauthorized)),
     };
@@ -269,35 +576,411 @@ fn login(req: &mut Request) -> IronResult<Response> {
         return Ok(Response::with(iron::status::Unauthorized));
     }
 
-    // Generate and set new token
-    user_info.token = generate_authorization_token();
-    if update_user_token(&user_info).is_ok() {
-        audit_event(
-            ServerEvents::TokenCreated,
-            &format!(
-                "Session token {} for user {} created",
-                user_info.token, user_info.username
-            ),
-        );
-        audit_event(
-            ServerEvents::LoginSuccess,
-            &format!("Login success for user {}", user_info.username),
-        );
-        successful_login_counter.inc();
-        Ok(Response::with((iron::status::Ok, user_info.token)))
-    } else {
+    // If the user has enrolled a TOTP secret, require a valid second factor
+    // supplied in the X-TOTP-Code header.
+    if let Ok(totp_secret) = get_etcd_key(&format!("/users/{}/totp_secret", user_info.username)) {
+        if !totp_secret.is_empty() {
+            let code = req
+                .headers
+                .get_raw("X-TOTP-Code")
+                .and_then(|values| values.get(0))
+                .and_then(|raw| std::str::from_utf8(raw).ok());
+
+            if !code.map(|code| verify_totp(&totp_secret, code)).unwrap_or(false) {
+                audit_event(
+                    ServerEvents::LoginFailureInvalidTotp,
+                    &format!(
+                        "Login failure for user {} due to missing or invalid TOTP code",
+                        user_info.username
+                    ),
+                );
+                unsuccessful_login_counter.inc();
+                return Ok(Response::with(iron::status::Unauthorized));
+            }
+        }
+    }
+
+    // Mint a fresh signed token for the session
+    user_info.token = match generate_authorization_token(&user_info.username) {
+        Ok(token) => token,
+        Err(e) => {
+            eprintln!("Unable to mint token: {}", e);
+            audit_event(
+                ServerEvents::LoginFailureTokenCreationFailure,
+                &format!(
+                    "Login failure for user {} due to token creation failure",
+                    user_info.username
+                ),
+            );
+            return Ok(Response::with(iron::status::InternalServerError));
+        }
+    };
+
+    audit_event(
+        ServerEvents::TokenCreated,
+        &format!(
+            "Session token {} for user {} created",
+            user_info.token, user_info.username
+        ),
+    );
+    audit_event(
+        ServerEvents::LoginSuccess,
+        &format!("Login success for user {}", user_info.username),
+    );
+    successful_login_counter.inc();
+    Ok(Response::with((iron::status::Ok, user_info.token)))
+}
+
+// Explicitly revoke the presented token by adding its `jti` to the etcd-backed
+// revocation list, so a stateless JWT can be invalidated before it expires.
+// Revocation is only honored when TOKEN_REVOCATION_ENABLED is set, so reject
+// logout otherwise rather than reporting a success that would not take effect.
+fn logout(req: &mut Request) -> IronResult<Response> {
+    if !*TOKEN_REVOCATION_ENABLED {
+        return Ok(Response::with((
+            iron::status::Gone,
+            "Token revocation is disabled",
+        )));
+    }
+
+    let token = match req.url.query() {
+        Some(val) => val.replace("token=", ""),
+        None => return Ok(Response::with((iron::status::BadRequest, "Token required"))),
+    };
+
+    let claims = match validate_token(&token) {
+        Ok(claims) => claims,
+        Err(_) => return Ok(Response::with((iron::status::Unauthorized, "Bad token"))),
+    };
+
+    match revoke_token_id(&claims.jti) {
+        Ok(_) => Ok(Response::with(iron::status::Ok)),
+        Err(e) => {
+            eprintln!("Unable to revoke token: {}", e);
+            Ok(Response::with(iron::status::InternalServerError))
+        }
+    }
+}
+
+// Enroll a TOTP second factor for the caller, authenticated by their current
+// password via Basic auth. A fresh 20-byte secret is generated, stored in etcd
+// and returned to the client as an `otpauth://` provisioning URI.
+fn totp_enroll(req: &mut Request) -> IronResult<Response> {
+    let auth = match req.headers.get::<iron::headers::Authorization<Basic>>() {
+        Some(auth) => auth,
+        None => return Ok(Response::with(iron::status::Unauthorized)),
+    };
+
+    let mut user_info = UserInfo::default();
+    user_info.username = auth.username.clone();
+    user_info.password = match auth.password.clone() {
+        Some(password) => password,
+        None => return Ok(Response::with(iron::status::Unauthorized)),
+    };
+
+    fetch_user_password(&mut user_info);
+    if !verify_password(&user_info) {
+        unsuccessful_login_counter.inc();
+        return Ok(Response::with(iron::status::Unauthorized));
+    }
+
+    let mut secret = [0u8; 20];
+    thread_rng().fill(&mut secret[..]);
+    let secret_b32 = data_encoding::BASE32_NOPAD.encode(&secret);
+
+    if let Err(e) = set_etcd_key(
+        &format!("/users/{}/totp_secret", user_info.username),
+        &secret_b32,
+        None,
+    ) {
+        eprintln!("Unable to store TOTP secret: {}", e);
+        return Ok(Response::with(iron::status::InternalServerError));
+    }
+
+    audit_event(
+        ServerEvents::TotpEnrolled,
+        &format!("TOTP second factor enrolled for user {}", user_info.username),
+    );
+
+    let uri = format!(
+        "otpauth://totp/simple-secrets:{user}?secret={secret}&issuer=simple-secrets",
+        user = user_info.username,
+        secret = secret_b32,
+    );
+    Ok(Response::with((iron::status::Ok, uri)))
+}
+
+// Mint a macaroon derived from the caller's identity, attenuated by a list of
+// first-party caveats supplied as a JSON array of predicate strings. The result
+// is strictly less powerful than the parent token: the caveats can only narrow
+// the op, secret, and validity window, never widen them.
+fn attenuate_token(req: &mut Request) -> IronResult<Response> {
+    let (subject, parent_scopes) = match req.extensions.get::<Authorization>() {
+        Some(auth) => (auth.subject.clone(), auth.scopes.clone()),
+        None => return Ok(Response::with(iron::status::Unauthorized)),
+    };
+
+    // When the parent is itself a macaroon, carry its caveats forward so the
+    // child inherits every existing restriction (its `secret` binding, its
+    // narrowed `op`, its expiry) and the new caveats can only add to them.
+    let parent_caveats = match extract_token(req) {
+        Some(ref token) if token.starts_with(MACAROON_PREFIX) => match decode_macaroon(token) {
+            Ok(parent) => parent.caveats,
+            Err(_) => return Ok(Response::with((iron::status::BadRequest, "Invalid macaroon"))),
+        },
+        // A session JWT parent bounds the child by its own expiry, so the
+        // macaroon can never outlive the token that minted it.
+        Some(ref token) if !*OPEN_AUTH => match validate_token(token) {
+            Ok(claims) => vec![format!("time < {}", claims.exp)],
+            Err(_) => return Ok(Response::with(iron::status::Unauthorized)),
+        },
+        _ => Vec::new(),
+    };
+
+    let body = match read_body(req) {
+        Ok(body) => body,
+        Err(_) => return Ok(Response::with(iron::status::BadRequest)),
+    };
+    let new_caveats: Vec<String> = match serde_json::from_str(&body) {
+        Ok(caveats) => caveats,
+        Err(_) => return Ok(Response::with((iron::status::BadRequest, "Invalid caveat list"))),
+    };
+
+    // A caveat may only narrow the parent token's authority: reject any `op`
+    // the caller does not already hold so an attenuated macaroon can never be
+    // more powerful than the token that minted it.
+    for caveat in &new_caveats {
+        if let Some(("op", "=", value)) = parse_caveat(caveat)
+            .as_ref()
+            .map(|(k, o, v)| (k.as_str(), o.as_str(), v.as_str()))
+        {
+            if !parent_scopes.iter().any(|scope| scope == value) {
+                audit_event(
+                    ServerEvents::MacaroonCaveatFailed,
+                    &format!("Refused to mint macaroon widening scope to {}", value),
+                );
+                return Ok(Response::with((iron::status::Forbidden, "Caveat widens authority")));
+            }
+        }
+    }
+
+    let mut caveats = parent_caveats;
+    caveats.extend(new_caveats);
+    let signature = derive_macaroon_signature(&subject, &caveats);
+    let macaroon = Macaroon {
+        identifier: subject.clone(),
+        caveats,
+        signature,
+    };
+
+    match serialize_macaroon(&macaroon) {
+        Ok(token) => {
+            audit_event(
+                ServerEvents::MacaroonMinted,
+                &format!("Macaroon minted for subject {}", subject),
+            );
+            Ok(Response::with((iron::status::Ok, token)))
+        }
+        Err(e) => {
+            eprintln!("Unable to serialize macaroon: {}", e);
+            Ok(Response::with(iron::status::InternalServerError))
+        }
+    }
+}
+
+// Shared guard for the admin endpoints: requires an `admin` scope on the
+// validated token published by AuthMiddleware.
+fn require_admin(req: &Request) -> bool {
+    req.extensions
+        .get::<Authorization>()
+        .map(|auth| auth.has_scope("admin"))
+        .unwrap_or(false)
+}
+
+fn read_body(req: &mut Request) -> Result<String> {
+    let mut body = String::new();
+    req.body
+        .read_to_string(&mut body)
+        .chain_err(|| "Unable to read request body")?;
+    Ok(body)
+}
+
+// The scopes a subject's login token carries, used as the ceiling when
+// attenuating a macaroon: a caveat may select from these but never add to them.
+fn base_scopes(subject: &str) -> Vec<String> {
+    let mut scopes = vec![String::from("read"), String::from("write")];
+    if is_admin(subject) {
+        scopes.push(String::from("admin"));
+    }
+    scopes
+}
+
+// Create or replace a role's grant list. Body is a JSON array of `Grant`.
+fn create_role(req: &mut Request) -> IronResult<Response> {
+    if !require_admin(req) {
+        return Ok(Response::with(iron::status::Forbidden));
+    }
+
+    let name = match req.extensions.get::<Router>() {
+        Some(params) => params.find("name").unwrap_or("").to_string(),
+        None => return Ok(Response::with(iron::status::BadRequest)),
+    };
+
+    let body = match read_body(req) {
+        Ok(body) => body,
+        Err(_) => return Ok(Response::with(iron::status::BadRequest)),
+    };
+    if serde_json::from_str::<Vec<Grant>>(&body).is_err() {
+        return Ok(Response::with((iron::status::BadRequest, "Invalid grant list")));
+    }
+
+    match set_etcd_key(&format!("/roles/{}", name), &body, None) {
+        Ok(_) => Ok(Response::with(iron::status::Ok)),
+        Err(e) => {
+            eprintln!("Unable to store role {}: {}", name, e);
+            Ok(Response::with(iron::status::InternalServerError))
+        }
+    }
+}
+
+// Bind a user to a set of roles. Body is a JSON array of role names.
+fn set_user_roles(req: &mut Request) -> IronResult<Response> {
+    if !require_admin(req) {
+        return Ok(Response::with(iron::status::Forbidden));
+    }
+
+    let name = match req.extensions.get::<Router>() {
+        Some(params) => params.find("name").unwrap_or("").to_string(),
+        None => return Ok(Response::with(iron::status::BadRequest)),
+    };
+
+    let body = match read_body(req) {
+        Ok(body) => body,
+        Err(_) => return Ok(Response::with(iron::status::BadRequest)),
+    };
+    if serde_json::from_str::<Vec<String>>(&body).is_err() {
+        return Ok(Response::with((iron::status::BadRequest, "Invalid role list")));
+    }
+
+    match set_etcd_key(&format!("/users/{}/roles", name), &body, None) {
+        Ok(_) => Ok(Response::with(iron::status::Ok)),
+        Err(e) => {
+            eprintln!("Unable to store roles for user {}: {}", name, e);
+            Ok(Response::with(iron::status::InternalServerError))
+        }
+    }
+}
+
+// Re-seal every stored secret under a freshly supplied master key. The new key
+// is installed as the next version in the ring and made active, then each
+// `/secrets/{uuid}/value` is opened under its recorded version and resealed
+// under the new one. Values already carrying the active version are skipped, so
+// a retried rotation is idempotent and a crash mid-pass is recoverable simply
+// by calling the endpoint again.
+fn rotate_key(req: &mut Request) -> IronResult<Response> {
+    if !require_admin(req) {
+        return Ok(Response::with(iron::status::Forbidden));
+    }
+
+    let body = match read_body(req) {
+        Ok(body) => body,
+        Err(_) => return Ok(Response::with(iron::status::BadRequest)),
+    };
+    let raw = match base64::decode(body.trim()) {
+        Ok(raw) => raw,
+        Err(_) => return Ok(Response::with((iron::status::BadRequest, "Key must be valid base64"))),
+    };
+    let new_key = match secretbox::Key::from_slice(&raw) {
+        Some(key) => key,
+        None => return Ok(Response::with((iron::status::BadRequest, "Key must decode to 32 bytes"))),
+    };
+
+    // Persist the wrapped key before it becomes active, so a restart (or a
+    // crash mid-pass) can rebuild the ring and reopen anything resealed under
+    // the new version. A retry with the key that is already active reuses its
+    // version, so values migrated by an earlier pass are recognised and skipped.
+    let new_version = {
+        let ring = KEY_RING.read().expect("key ring lock poisoned");
+        if ring.keys.get(&ring.active) == Some(&new_key) {
+            ring.active
+        } else {
+            ring.active + 1
+        }
+    };
+    if let Err(e) = persist_master_key(new_version, &new_key) {
+        eprintln!("Unable to persist rotated key: {}", e);
+        return Ok(Response::with(iron::status::InternalServerError));
+    }
+
+    // Install the new key as the next version and make it active. Older
+    // versions stay in the ring so blobs not yet migrated remain openable.
+    {
+        let mut ring = KEY_RING.write().expect("key ring lock poisoned");
+        ring.keys.insert(new_version, new_key);
+        ring.active = new_version;
+    }
+
+    audit_event(
+        ServerEvents::KeyRotationStart,
+        &format!("Key rotation started, new active key version {}", new_version),
+    );
+
+    let secrets = match list_etcd_keys("/secrets") {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("Unable to list secrets for rotation: {}", e);
+            return Ok(Response::with(iron::status::InternalServerError));
+        }
+    };
+
+    let mut migrated = 0u64;
+    for key in secrets.iter().filter(|key| key.ends_with("/value")) {
+        let sealed = match get_etcd_key(key) {
+            Ok(sealed) if !sealed.is_empty() => sealed,
+            _ => continue,
+        };
+        // Idempotent: anything already sealed under the active version is done.
+        if sealed_version(&sealed) == Some(new_version) {
+            continue;
+        }
+
+        let plaintext = match open_secret(&sealed) {
+            Ok(plaintext) => plaintext,
+            Err(e) => {
+                eprintln!("Unable to open {} during rotation: {}", key, e);
+                return Ok(Response::with(iron::status::InternalServerError));
+            }
+        };
+        let resealed = match seal_secret(&plaintext) {
+            Ok(resealed) => resealed,
+            Err(e) => {
+                eprintln!("Unable to reseal {} during rotation: {}", key, e);
+                return Ok(Response::with(iron::status::InternalServerError));
+            }
+        };
+        if let Err(e) = set_etcd_key(key, &resealed, None) {
+            eprintln!("Unable to store resealed {}: {}", key, e);
+            return Ok(Response::with(iron::status::InternalServerError));
+        }
+
+        migrated += 1;
         audit_event(
-            ServerEvents::LoginFailureTokenCreationFailure,
-            &format!(
-                "Login failure for user {} due to token creation failure",
-                user_info.username
-            ),
+            ServerEvents::KeyRotationSecret,
+            &format!("Secret {} resealed under key version {}", key, new_version),
         );
-        Ok(Response::with(iron::status::InternalServerError))
     }
+
+    audit_event(
+        ServerEvents::KeyRotationComplete,
+        &format!(
+            "Key rotation complete, {} secrets resealed under version {}",
+            migrated, new_version
+        ),
+    );
+    Ok(Response::with((iron::status::Ok, format!("{}", migrated))))
 }
 
-fn generate_authorization_token() -> String {
+fn generate_token_id() -> String {
     let mut rng = thread_rng();
     std::iter::repeat(())
         .map(|()| rng.sample(Alphanumeric))
@@ -305,14 +988,339 @@ fn generate_authorization_token() -> String {
         .collect()
 }
 
-fn update_user_token(user_info: &UserInfo) -> Result<()> {
+// Mint a signed HS256 JWT for `username` granting the default read/write
+// scopes, plus `admin` when the user is bound to the reserved admin role. The
+// token is self-validating, so no etcd round trip is needed on subsequent
+// requests.
+fn generate_authorization_token(username: &str) -> Result<String> {
+    let iat = unix_now();
+    let mut scopes = vec![String::from("read"), String::from("write")];
+    if is_admin(username) {
+        scopes.push(String::from("admin"));
+    }
+    let claims = Claims {
+        sub: username.to_string(),
+        iat,
+        exp: iat + *TOKEN_EXPIRATION_SECS,
+        jti: generate_token_id(),
+        scopes,
+        secret: None,
+    };
+
+    encode(&Header::new(Algorithm::HS256), &claims, JWT_SECRET.as_ref())
+        .chain_err(|| "Unable to sign authorization token")
+}
+
+// Seal a secret value for storage at rest under the key ring's active version.
+// The sealed blob is self-describing: a leading key-version byte, a fresh
+// 24-byte nonce, and the XSalsa20-Poly1305 ciphertext, base64-encoded for
+// storage in etcd.
+fn seal_secret(plaintext: &str) -> Result<String> {
+    let ring = KEY_RING.read().expect("key ring lock poisoned");
+    let version = ring.active;
+    let key = ring.keys.get(&version).ok_or("Active master key missing")?;
+    seal_under(plaintext, version, key)
+}
+
+fn seal_under(plaintext: &str, version: u8, key: &secretbox::Key) -> Result<String> {
+    let mut nonce_bytes = [0u8; secretbox::NONCEBYTES];
+    thread_rng().fill(&mut nonce_bytes[..]);
+    let nonce = secretbox::Nonce::from_slice(&nonce_bytes)
+        .ok_or("Unable to construct secretbox nonce")?;
+    let ciphertext = secretbox::seal(plaintext.as_bytes(), &nonce, key);
+
+    let mut blob = Vec::with_capacity(1 + nonce_bytes.len() + ciphertext.len());
+    blob.push(version);
+    blob.extend_from_slice(&nonce_bytes);
+    blob.extend_from_slice(&ciphertext);
+    Ok(base64::encode(&blob))
+}
+
+// Open a sealed secret produced by `seal_secret`, selecting the key named by
+// the blob's leading version byte. Returns a generic error on any failure so a
+// caller can never distinguish a decode, length, or tag-check failure from a
+// plain lookup miss.
+fn open_secret(sealed: &str) -> Result<String> {
+    let blob = base64::decode(sealed).or(Err("Unable to open secret"))?;
+    if blob.len() < 1 + secretbox::NONCEBYTES {
+        Err("Unable to open secret")?;
+    }
+
+    let version = blob[0];
+    let ring = KEY_RING.read().expect("key ring lock poisoned");
+    let key = ring.keys.get(&version).ok_or("Unable to open secret")?;
+
+    let (nonce_bytes, ciphertext) = blob[1..].split_at(secretbox::NONCEBYTES);
+    let nonce =
+        secretbox::Nonce::from_slice(nonce_bytes).ok_or("Unable to open secret")?;
+    let plaintext =
+        secretbox::open(ciphertext, &nonce, key).or(Err("Unable to open secret"))?;
+
+    String::from_utf8(plaintext).map_err(|_| "Unable to open secret".into())
+}
+
+// Read the key version a sealed blob was written under without decrypting it,
+// so a rotation can cheaply skip values already migrated to the active key.
+fn sealed_version(sealed: &str) -> Option<u8> {
+    base64::decode(sealed).ok().and_then(|blob| blob.first().copied())
+}
+
+// Persist a rotated master key, wrapped under the bootstrap MASTER_KEY, at
+// `/keys/{version}` so the versioned ring can be rebuilt after a restart. This
+// is written before the new version is made active, so a crash mid-rotation
+// leaves every key needed to open the secrets already on disk resealable again.
+fn persist_master_key(version: u8, key: &secretbox::Key) -> Result<()> {
+    let mut nonce_bytes = [0u8; secretbox::NONCEBYTES];
+    thread_rng().fill(&mut nonce_bytes[..]);
+    let nonce = secretbox::Nonce::from_slice(&nonce_bytes)
+        .ok_or("Unable to construct secretbox nonce")?;
+    let ciphertext = secretbox::seal(key.as_ref(), &nonce, &MASTER_KEY);
+
+    let mut blob = Vec::with_capacity(nonce_bytes.len() + ciphertext.len());
+    blob.extend_from_slice(&nonce_bytes);
+    blob.extend_from_slice(&ciphertext);
+    set_etcd_key(&format!("/keys/{}", version), &base64::encode(&blob), None)
+}
+
+// Unwrap a key persisted by `persist_master_key` with the bootstrap MASTER_KEY.
+fn unwrap_master_key(wrapped: &str) -> Result<secretbox::Key> {
+    let blob = base64::decode(wrapped).or(Err("Unable to unwrap key"))?;
+    if blob.len() < secretbox::NONCEBYTES {
+        Err("Unable to unwrap key")?;
+    }
+    let (nonce_bytes, ciphertext) = blob.split_at(secretbox::NONCEBYTES);
+    let nonce = secretbox::Nonce::from_slice(nonce_bytes).ok_or("Unable to unwrap key")?;
+    let raw = secretbox::open(ciphertext, &nonce, &MASTER_KEY).or(Err("Unable to unwrap key"))?;
+    secretbox::Key::from_slice(&raw).ok_or_else(|| "Unable to unwrap key".into())
+}
+
+// Rebuild the versioned key ring from any keys persisted by earlier rotations,
+// installing each wrapped key and making the highest version active so secrets
+// resealed before the restart stay openable.
+fn restore_key_ring() {
+    let keys = match list_etcd_keys("/keys") {
+        Ok(keys) => keys,
+        Err(e) => {
+            eprintln!("Unable to list persisted keys: {}", e);
+            return;
+        }
+    };
+
+    let mut ring = KEY_RING.write().expect("key ring lock poisoned");
+    for path in keys {
+        let version = match path.rsplit('/').next().and_then(|v| v.parse::<u8>().ok()) {
+            Some(version) => version,
+            None => continue,
+        };
+        let wrapped = match get_etcd_key(&path) {
+            Ok(ref wrapped) if !wrapped.is_empty() => wrapped.clone(),
+            _ => continue,
+        };
+        match unwrap_master_key(&wrapped) {
+            Ok(key) => {
+                ring.keys.insert(version, key);
+                if version > ring.active {
+                    ring.active = version;
+                }
+            }
+            Err(e) => eprintln!("Unable to restore key version {}: {}", version, e),
+        }
+    }
+}
+
+// Compare two tokens in constant time to avoid leaking a match length through a
+// timing side channel.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+type HmacSha1 = Hmac<Sha1>;
+
+// Compute the 6-digit HOTP value for a shared secret and counter per RFC 4226
+// (the building block of TOTP): HMAC-SHA1 over the big-endian counter, dynamic
+// truncation, modulo 10^6.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = HmacSha1::new_varkey(secret).expect("HMAC accepts keys of any size");
+    mac.input(&counter.to_be_bytes());
+    let code = mac.result().code();
+
+    let offset = (code[code.len() - 1] & 0x0f) as usize;
+    let binary = ((u32::from(code[offset]) & 0x7f) << 24)
+        | (u32::from(code[offset + 1]) << 16)
+        | (u32::from(code[offset + 2]) << 8)
+        | u32::from(code[offset + 3]);
+
+    binary % 1_000_000
+}
+
+// Verify a 6-digit TOTP code (RFC 6238) against a base32 secret, tolerating
+// ±1 time step of clock skew around the current 30-second window.
+fn verify_totp(secret_b32: &str, code: &str) -> bool {
+    let secret = match data_encoding::BASE32_NOPAD.decode(secret_b32.as_bytes()) {
+        Ok(secret) => secret,
+        Err(_) => return false,
+    };
+    let expected: u32 = match code.trim().parse() {
+        Ok(value) => value,
+        Err(_) => return false,
+    };
+
+    let step = unix_now() / 30;
+    (-1i64..=1).any(|skew| {
+        let counter = (step as i64 + skew) as u64;
+        constant_time_eq(&format!("{:06}", hotp(&secret, counter)), &format!("{:06}", expected))
+    })
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+// A serialized macaroon: an identifier bound to an ordered list of first-party
+// caveats, chained under the server's root key into a single signature.
+#[derive(Debug, Serialize, Deserialize)]
+struct Macaroon {
+    identifier: String,
+    caveats: Vec<String>,
+    signature: String,
+}
+
+const MACAROON_PREFIX: &str = "M1.";
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_varkey(key).expect("HMAC accepts keys of any size");
+    mac.input(message);
+    mac.result().code().as_slice().to_vec()
+}
+
+// Derive a macaroon signature by chaining the root key over the identifier and
+// each caveat in order, so no caveat can be dropped or altered without the key.
+fn derive_macaroon_signature(identifier: &str, caveats: &[String]) -> String {
+    let mut signature = hmac_sha256(MACAROON_ROOT_KEY.as_bytes(), identifier.as_bytes());
+    for caveat in caveats {
+        signature = hmac_sha256(&signature, caveat.as_bytes());
+    }
+    data_encoding::HEXLOWER.encode(&signature)
+}
+
+fn serialize_macaroon(macaroon: &Macaroon) -> Result<String> {
+    let json = serde_json::to_vec(macaroon).chain_err(|| "Unable to serialize macaroon")?;
+    Ok(format!("{}{}", MACAROON_PREFIX, base64::encode(&json)))
+}
+
+// Decode a serialized macaroon back into its struct without validating the
+// signature, used both by `validate_macaroon` and when chaining a child off a
+// parent macaroon's caveats.
+fn decode_macaroon(token: &str) -> Result<Macaroon> {
+    let encoded = &token[MACAROON_PREFIX.len()..];
+    let bytes = base64::decode(encoded).or(Err("Invalid macaroon"))?;
+    serde_json::from_slice(&bytes).or_else(|_| Err("Invalid macaroon".into()))
+}
+
+// Split a first-party caveat predicate into (key, operator, value), e.g.
+// "secret = db-password" -> ("secret", "=", "db-password").
+fn parse_caveat(caveat: &str) -> Option<(String, String, String)> {
+    let parts: Vec<&str> = caveat.splitn(3, ' ').collect();
+    if parts.len() == 3 {
+        Some((parts[0].to_string(), parts[1].to_string(), parts[2].to_string()))
+    } else {
+        None
+    }
+}
+
+// Verify a macaroon's signature against the root key and evaluate its
+// context-independent caveats (currently `time < <unix>`), translating the
+// `op`/`secret` caveats into scopes and a secret restriction for the handlers
+// to enforce. Every caveat must be understood and satisfied.
+fn validate_macaroon(token: &str) -> Result<Claims> {
+    let macaroon = decode_macaroon(token)?;
+
+    let expected = derive_macaroon_signature(&macaroon.identifier, &macaroon.caveats);
+    if !constant_time_eq(&expected, &macaroon.signature) {
+        audit_event(
+            ServerEvents::TokenSignatureInvalid,
+            "Macaroon validation failed, signature invalid",
+        );
+        return Err("Macaroon signature invalid".into());
+    }
+
+    // The minter's login authority is the ceiling for the first `op` caveat.
+    // Each further `op` caveat may only narrow what survives so far, so two
+    // distinct `op` caveats leave no permitted operation — a child can never
+    // regain an operation its parent's chain already dropped.
+    let authority = base_scopes(&macaroon.identifier);
+    let mut selected: Option<Vec<String>> = None;
+    let mut secret = None;
+    for caveat in &macaroon.caveats {
+        let (key, op, value) = match parse_caveat(caveat) {
+            Some(parsed) => parsed,
+            None => return Err(macaroon_caveat_failed(caveat)),
+        };
+        match (key.as_str(), op.as_str()) {
+            ("op", "=") => {
+                let permitted = selected
+                    .as_ref()
+                    .map(|scopes| scopes.as_slice())
+                    .unwrap_or(authority.as_slice());
+                if !permitted.contains(&value) {
+                    return Err(macaroon_caveat_failed(caveat));
+                }
+                selected = Some(vec![value]);
+            }
+            ("secret", "=") => {
+                // A second `secret` caveat naming a different secret cannot be
+                // satisfied alongside the first, so a child cannot rebind it.
+                if secret.as_ref().map(|s| s != &value).unwrap_or(false) {
+                    return Err(macaroon_caveat_failed(caveat));
+                }
+                secret = Some(value);
+            }
+            ("time", "<") => {
+                let deadline: u64 = value.parse().map_err(|_| macaroon_caveat_failed(caveat))?;
+                if unix_now() >= deadline {
+                    return Err(macaroon_caveat_failed(caveat));
+                }
+            }
+            _ => return Err(macaroon_caveat_failed(caveat)),
+        }
+    }
+
+    // A macaroon with no `op` caveat inherits the minter's read/write authority.
+    let scopes =
+        selected.unwrap_or_else(|| vec![String::from("read"), String::from("write")]);
+
+    Ok(Claims {
+        sub: macaroon.identifier,
+        exp: 0,
+        iat: 0,
+        jti: String::new(),
+        scopes,
+        secret,
+    })
+}
+
+fn macaroon_caveat_failed(caveat: &str) -> Error {
+    audit_event(
+        ServerEvents::MacaroonCaveatFailed,
+        &format!("Macaroon caveat not satisfied: {}", caveat),
+    );
+    format!("Macaroon caveat not satisfied: {}", caveat).into()
+}
+
+// Record a token id in the etcd-backed revocation list so an explicit logout
+// can invalidate an otherwise still-valid JWT before it expires. The entry is
+// given the full token lifetime (TOKEN_EXPIRATION_SECS) as a TTL, which covers
+// whatever validity the token has left, so it is reaped automatically.
+fn revoke_token_id(jti: &str) -> Result<()> {
     set_etcd_key(
-        &format!("/session_tokens/{}", user_info.token),
-        &user_info.username,
+        &format!("/revoked_tokens/{}", jti),
+        "revoked",
         Some(*TOKEN_EXPIRATION_SECS),
-    )?;
+    )
+}
 
-    Ok(())
+fn is_token_revoked(jti: &str) -> bool {
+    get_etcd_key(&format!("/revoked_tokens/{}", jti))
+        .map(|value| !value.is_empty())
+        .unwrap_or(false)
 }
 
 fn set_secret(req: &mut Request) -> IronResult<Response> {
@@ -329,29 +1337,44 @@ fn set_secret(req: &mut Request) -> IronResult<Response> {
         None => return Ok(Response::with(iron::status::BadRequest)),
     };
 
-    // Validate token
-    let token;
-    if let Some(val) = req.url.query() {
-        token = val.replace("token=", "");
-    } else {
+    // Authorization is established by AuthMiddleware; enforce the write scope.
+    let username;
+    let secret_caveat;
+    match req.extensions.get::<Authorization>() {
+        Some(auth) if auth.has_scope("write") => {
+            username = auth.subject.clone();
+            secret_caveat = auth.secret.clone();
+        }
+        Some(_) => {
+            audit_event(
+                ServerEvents::SecretCreateFailureInvalidToken,
+                &format!("Secret {} failed set, token lacks write scope", args.0),
+            );
+            secrets_set_access_denied_counter.inc();
+            return Ok(Response::with((iron::status::Unauthorized, "Insufficient scope")));
+        }
+        None => return Ok(Response::with(iron::status::Unauthorized)),
+    }
+
+    // Enforce a macaroon `secret =` caveat, if any.
+    if secret_caveat.map(|s| s != args.0).unwrap_or(false) {
         audit_event(
-            ServerEvents::SecretCreateFailureNoToken,
-            &format!("Secret {} failed set, no token entered attempt", args.0),
+            ServerEvents::MacaroonCaveatFailed,
+            &format!("Macaroon not permitted to set secret {}", args.0),
         );
         secrets_set_access_denied_counter.inc();
-        return Ok(Response::with((iron::status::BadRequest, "Token required")));
+        return Ok(Response::with((iron::status::Forbidden, "Access denied")));
     }
 
-    let username;
-    if let Ok(val) = validate_token(&token) {
-        username = val;
-    } else {
+    // Enforce RBAC before touching etcd.
+    let resource = format!("/secrets/{}", args.0);
+    if !authorize_secret(&username, &resource, true) {
         audit_event(
-            ServerEvents::SecretCreateFailureInvalidToken,
-            &format!("Secret {} failed set, invalid token attempt", args.0),
+            ServerEvents::SecretAccessDenied,
+            &format!("User {} denied write access to secret {}", username, args.0),
         );
         secrets_set_access_denied_counter.inc();
-        return Ok(Response::with((iron::status::Unauthorized, "Bad token")));
+        return Ok(Response::with((iron::status::Forbidden, "Access denied")));
     }
 
     // Set secret
@@ -368,7 +1391,22 @@ fn set_secret(req: &mut Request) -> IronResult<Response> {
 
         return Ok(Response::with(iron::status::InternalServerError));
     }
-    if let Err(e) = set_etcd_key(&format!("/secrets/{}/value", uuid), args.1, None) {
+    let sealed = match seal_secret(args.1) {
+        Ok(sealed) => sealed,
+        Err(e) => {
+            eprintln!("Unable to seal secret value: {}", e);
+            audit_event(
+                ServerEvents::SecretCreateFailure,
+                &format!(
+                    "Unable to set secret {} by user {}, internal error",
+                    args.0, username
+                ),
+            );
+
+            return Ok(Response::with(iron::status::InternalServerError));
+        }
+    };
+    if let Err(e) = set_etcd_key(&format!("/secrets/{}/value", uuid), &sealed, None) {
         eprintln!("Unable to set secret value: {}", e);
         audit_event(
             ServerEvents::SecretCreateFailure,
@@ -410,50 +1448,101 @@ fn set_etcd_key(key: &str, value: &str, expiration: Option<u64>) -> Result<()> {
     Ok(())
 }
 
-fn get_etcd_key(key: &str) -> Result<String> {
+// Recursively list the keys carrying a value under an etcd directory, returning
+// their full key paths. Used by key rotation to walk every stored secret.
+fn list_etcd_keys(prefix: &str) -> Result<Vec<String>> {
     let mut core = Core::new()?;
     let client = match new_etcd_client(&core) {
         Ok(client) => client,
         Err(_) => Err("Unable to create etcd client")?,
     };
 
-    let mut value = None;
+    let options = kv::GetOptions {
+        recursive: true,
+        ..Default::default()
+    };
+    let mut keys = Vec::new();
     {
-        let get_token = kv::get(&client, key, kv::GetOptions::default()).and_then(|response| {
-            value = response.data.node.value;
-
+        let get = kv::get(&client, prefix, options).and_then(|response| {
+            collect_node_keys(&response.data.node, &mut keys);
             Ok(())
         });
-        core.run(get_token)
-            .or_else(|_| Err(format!("Unable to fetch etcd key {}", key)))?;
+        core.run(get)
+            .or_else(|_| Err(format!("Unable to list etcd keys under {}", prefix)))?;
     }
 
-    Ok(value.unwrap_or_else(|| String::from("")))
+    Ok(keys)
+}
+
+fn collect_node_keys(node: &kv::Node, keys: &mut Vec<String>) {
+    if node.value.is_some() {
+        if let Some(ref key) = node.key {
+            keys.push(key.clone());
+        }
+    }
+    for child in &node.nodes {
+        collect_node_keys(child, keys);
+    }
 }
 
-fn validate_token(token: &str) -> Result<String> {
+fn get_etcd_key(key: &str) -> Result<String> {
     let mut core = Core::new()?;
     let client = match new_etcd_client(&core) {
         Ok(client) => client,
         Err(_) => Err("Unable to create etcd client")?,
     };
 
-    let mut username = None;
+    let mut value = None;
     {
-        let fetch_token = kv::get(
-            &client,
-            &format!("/session_tokens/{}", token),
-            kv::GetOptions::default(),
-        ).and_then(|response| {
-            username = response.data.node.value;
+        let get_token = kv::get(&client, key, kv::GetOptions::default()).and_then(|response| {
+            value = response.data.node.value;
 
             Ok(())
         });
-        core.run(fetch_token)
-            .or_else(|_| Err(format!("Token {} not found", token)))?;
+        core.run(get_token)
+            .or_else(|_| Err(format!("Unable to fetch etcd key {}", key)))?;
+    }
+
+    Ok(value.unwrap_or_else(|| String::from("")))
+}
+
+// Verify a session JWT's signature and expiry locally, returning its claims.
+// An explicit etcd revocation-list lookup (keyed by `jti`) is only consulted
+// once the signature is known good, so the common path needs no network hop.
+fn validate_token(token: &str) -> Result<Claims> {
+    use jsonwebtoken::errors::ErrorKind;
+
+    if token.starts_with(MACAROON_PREFIX) {
+        return validate_macaroon(token);
+    }
+
+    let validation = Validation::new(Algorithm::HS256);
+    let claims = match decode::<Claims>(token, JWT_SECRET.as_ref(), &validation) {
+        Ok(data) => data.claims,
+        Err(e) => match *e.kind() {
+            ErrorKind::ExpiredSignature => {
+                audit_event(
+                    ServerEvents::TokenExpired,
+                    "Token validation failed, token expired",
+                );
+                return Err("Token expired".into());
+            }
+            ErrorKind::InvalidSignature => {
+                audit_event(
+                    ServerEvents::TokenSignatureInvalid,
+                    "Token validation failed, signature invalid",
+                );
+                return Err("Token signature invalid".into());
+            }
+            _ => return Err(format!("Token {} not valid", token).into()),
+        },
+    };
+
+    if *TOKEN_REVOCATION_ENABLED && is_token_revoked(&claims.jti) {
+        return Err(format!("Token {} has been revoked", claims.jti).into());
     }
 
-    Ok(username.unwrap_or_else(|| String::from("")))
+    Ok(claims)
 }
 
 fn fetch_secret(req: &mut Request) -> IronResult<Response> {
@@ -465,43 +1554,71 @@ fn fetch_secret(req: &mut Request) -> IronResult<Response> {
         None => return Ok(Response::with(iron::status::BadRequest)), // This should never happen
     };
 
-    // Validate token
-    let token;
-    if let Some(val) = req.url.query() {
-        token = val.replace("token=", "");
-    } else {
+    // Authorization is established by AuthMiddleware; enforce the read scope.
+    let username;
+    let secret_caveat;
+    match req.extensions.get::<Authorization>() {
+        Some(auth) if auth.has_scope("read") => {
+            username = auth.subject.clone();
+            secret_caveat = auth.secret.clone();
+        }
+        Some(_) => {
+            audit_event(
+                ServerEvents::SecretFetchFailureInvalidToken,
+                &format!("Secret {} failed fetch, token lacks read scope", name),
+            );
+            secrets_fetch_access_denied_counter.inc();
+            return Ok(Response::with((iron::status::Unauthorized, "Insufficient scope")));
+        }
+        None => return Ok(Response::with(iron::status::Unauthorized)),
+    }
+
+    // Enforce a macaroon `secret =` caveat, if any.
+    if secret_caveat.map(|s| s != name).unwrap_or(false) {
         audit_event(
-            ServerEvents::SecretFetchFailureNoToken,
-            &format!("Secret {} failed fetch, no token entered attempt", name),
+            ServerEvents::MacaroonCaveatFailed,
+            &format!("Macaroon not permitted to fetch secret {}", name),
         );
         secrets_fetch_access_denied_counter.inc();
-        return Ok(Response::with((iron::status::BadRequest, "Token required")));
+        return Ok(Response::with((iron::status::Forbidden, "Access denied")));
     }
 
-    let username;
-    if let Ok(val) = validate_token(&token) {
-        username = val;
-    } else {
+    // Enforce RBAC before touching etcd.
+    let resource = format!("/secrets/{}", name);
+    if !authorize_secret(&username, &resource, false) {
         audit_event(
-            ServerEvents::SecretFetchFailureInvalidToken,
-            &format!("Secret {} failed fetch, invalid token attempt", name),
+            ServerEvents::SecretAccessDenied,
+            &format!("User {} denied read access to secret {}", username, name),
         );
         secrets_fetch_access_denied_counter.inc();
-        return Ok(Response::with((iron::status::Unauthorized, "Bad token")));
+        return Ok(Response::with((iron::status::Forbidden, "Access denied")));
     }
 
     // Fetch secret
     let uuid = uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_DNS, name.as_bytes());
     let value = get_etcd_key(&format!("/secrets/{}/value", uuid));
     match value {
-        Ok(value) => {
-            audit_event(
-                ServerEvents::SecretFetchSuccess,
-                &format!("Secret {} UUID {} fetched by user {}", name, uuid, username),
-            );
-            secrets_fetch_counter.inc();
-            Ok(Response::with((iron::status::Ok, value)))
-        }
+        Ok(sealed) => match open_secret(&sealed) {
+            Ok(value) => {
+                audit_event(
+                    ServerEvents::SecretFetchSuccess,
+                    &format!("Secret {} UUID {} fetched by user {}", name, uuid, username),
+                );
+                secrets_fetch_counter.inc();
+                Ok(Response::with((iron::status::Ok, value)))
+            }
+            Err(e) => {
+                eprintln!("Unable to open secret: {}", e);
+                audit_event(
+                    ServerEvents::SecretDecryptFailure,
+                    &format!(
+                        "Secret {} UUID {} failed to decrypt for user {}, possible tampering",
+                        name, uuid, username
+                    ),
+                );
+                Ok(Response::with((iron::status::BadRequest, "Invalid secret")))
+            }
+        },
         Err(e) => {
             eprintln!("Unable to fetch secret: {}", e);
             audit_event(
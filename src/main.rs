@@ -8,7 +8,23 @@ extern crate argonautica;
 extern crate rand;
 extern crate uuid;
 extern crate fruently;
+extern crate base64;
+extern crate sodiumoxide;
+extern crate subtle;
+extern crate hyper_tls;
+extern crate native_tls;
+extern crate serde;
+extern crate serde_json;
+extern crate jsonwebtoken;
+extern crate rusqlite;
+extern crate opentelemetry;
+extern crate opentelemetry_otlp;
+extern crate hmac;
+extern crate sha1;
+extern crate data_encoding;
 
+#[macro_use]
+extern crate serde_derive;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
@@ -25,6 +41,22 @@ use fruently::forwardable::JsonForwardable;
 
 use rand::{Rng, thread_rng};
 use rand::distributions::Alphanumeric;
+use sodiumoxide::crypto::secretbox;
+use subtle::ConstantTimeEq;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use hyper_tls::HttpsConnector;
+use jsonwebtoken::{decode, encode, Algorithm, Header, Validation};
+use opentelemetry::{global, KeyValue};
+use opentelemetry::trace::{mark_span_as_active, Span, Tracer};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::Context;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::RwLock;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use errors::*;
 
@@ -45,6 +77,70 @@ lazy_static! {
         }
     };
 
+    // Credentials used to authenticate against an etcd cluster that has
+    // authentication enabled. Both must be present for credentials to be sent.
+    static ref ETCD_USERNAME: Option<String> = std::env::var("ETCD_USERNAME").ok();
+    static ref ETCD_PASSWORD: Option<String> = std::env::var("ETCD_PASSWORD").ok();
+
+    // Optional TLS material for talking to an https:// etcd cluster: a PEM CA
+    // bundle to trust and a PKCS#12 client identity (with its passphrase) for
+    // mutual TLS. When the member URLs are https:// an HTTPS connector is built
+    // regardless; these only customize trust and client authentication.
+    static ref ETCD_CA_CERT: Option<String> = std::env::var("ETCD_CA_CERT").ok();
+    static ref ETCD_CLIENT_CERT: Option<String> = std::env::var("ETCD_CLIENT_CERT").ok();
+    static ref ETCD_CLIENT_KEY_PASSWORD: String =
+        std::env::var("ETCD_CLIENT_KEY_PASSWORD").unwrap_or_default();
+
+    // When set, login issues stateless HS256 JWTs instead of opaque etcd-stored
+    // tokens, so every subsequent request validates locally with no etcd round
+    // trip. Revocation-sensitive deployments can leave this unset to keep the
+    // opaque-token path.
+    static ref JWT_SESSION_TOKENS: bool = {
+        std::env::var("JWT_SESSION_TOKENS")
+            .map(|val| val == "1" || val.eq_ignore_ascii_case("true"))
+            .unwrap_or(false)
+    };
+
+    // Secret used to sign and verify HS256 session JWTs. Only required when
+    // JWT_SESSION_TOKENS is enabled.
+    static ref JWT_SECRET: String = {
+        std::env::var("JWT_SECRET").expect("JWT_SECRET environment variable must be set")
+    };
+
+    // Master key used to seal secret values at rest, read from
+    // SECRETS_MASTER_KEY as 32 base64-encoded bytes and decoded once at startup.
+    static ref MASTER_KEY: secretbox::Key = {
+        let encoded = std::env::var("SECRETS_MASTER_KEY")
+            .expect("SECRETS_MASTER_KEY environment variable must be set");
+        let raw = base64::decode(&encoded).expect("SECRETS_MASTER_KEY must be valid base64");
+        secretbox::Key::from_slice(&raw).expect("SECRETS_MASTER_KEY must decode to 32 bytes")
+    };
+
+    // Storage backend selection. "etcd" (the default) keeps the original
+    // etcd-backed store; "sqlite" runs entirely against a local database for
+    // deployments without an etcd cluster.
+    static ref STORAGE_BACKEND: String =
+        std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| String::from("etcd"));
+    static ref SQLITE_PATH: String =
+        std::env::var("SQLITE_PATH").unwrap_or_else(|_| String::from("simple-secrets.db"));
+
+    // The configured persistence backend, behind the SecretStore trait so the
+    // HTTP handlers never name a concrete store.
+    static ref STORE: Box<dyn SecretStore> = {
+        match STORAGE_BACKEND.as_str() {
+            "sqlite" => Box::new(
+                SqliteStore::open(&SQLITE_PATH).expect("Unable to open sqlite store")
+            ),
+            _ => Box::new(EtcdStore),
+        }
+    };
+
+    // OTLP span exporter endpoint. Spans are shipped here in addition to the
+    // fire-and-forget fluentd audit events, giving operators per-request latency
+    // breakdowns the coarse events cannot.
+    static ref OTLP_ENDPOINT: String = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+        .unwrap_or_else(|_| String::from("http://localhost:4317"));
+
     // Application instance SPIFFE ID
     static ref SPIFFE_ID: &'static str = "spiffe://example.org/simple-secrets";
 
@@ -57,6 +153,13 @@ lazy_static! {
         }
     };
     static ref fluentd_client: Fluent<'static, &'static str> = Fluent::new(*FLUENTD_FORWARD_ADDR, *SPIFFE_ID);
+
+    // Registry of watch subscribers keyed by secret UUID. Each connected
+    // `/watch` client contributes a channel sender; a single per-UUID etcd
+    // watch thread fans every change out to all of them, so one etcd watch
+    // serves many connections.
+    static ref WATCH_REGISTRY: RwLock<HashMap<String, Vec<Sender<String>>>> =
+        RwLock::new(HashMap::new());
 }
 
 mod errors {
@@ -86,19 +189,123 @@ fn audit_event(title: &str, content: &str) {
     });
 }
 
+// Extracts W3C trace-context headers from an inbound Iron request so a request
+// span can be stitched into a caller's existing trace.
+struct HeaderExtractor<'a>(&'a iron::headers::Headers);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .get_raw(key)
+            .and_then(|raw| raw.get(0))
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter().map(|header| header.name()).collect()
+    }
+}
+
+// Install the OTLP span exporter and the W3C trace-context propagator, tagging
+// every span with the instance's SPIFFE ID as the service name.
+fn init_tracer() {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    if let Err(e) = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(OTLP_ENDPOINT.as_str()),
+        )
+        .with_trace_config(
+            opentelemetry::sdk::trace::config().with_resource(opentelemetry::sdk::Resource::new(
+                vec![KeyValue::new("service.name", SPIFFE_ID.to_string())],
+            )),
+        )
+        .install_simple()
+    {
+        eprintln!("Unable to initialize OTLP tracer: {}", e);
+    }
+}
+
+fn tracer() -> global::BoxedTracer {
+    global::tracer("simple-secrets")
+}
+
+// Recover the parent trace context carried by the request's headers, so request
+// spans continue an upstream trace when one is present.
+fn parent_context(req: &Request) -> Context {
+    global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(&req.headers))
+    })
+}
+
 fn main() {
+    sodiumoxide::init().expect("Unable to initialize libsodium");
+    lazy_static::initialize(&MASTER_KEY);
+    init_tracer();
+    if *JWT_SESSION_TOKENS {
+        lazy_static::initialize(&JWT_SECRET);
+    }
+    lazy_static::initialize(&STORE);
+
     let mut router = Router::new();
     router.get("/login", login, "login");
     router.get("/get/:name", fetch_secret, "get_secret");
     router.post("/set/:name/:value", set_secret, "set_secret");
+    router.post("/roles/:name", create_role, "create_role");
+    router.post("/users/:name/roles", set_user_roles, "set_user_roles");
+    router.get("/watch/:name", watch_secret, "watch_secret");
 
     Iron::new(router).http("0.0.0.0:3000").unwrap();
     audit_event("SERVER_START", &format!("New instance of secret-server started: {}", *SPIFFE_ID));
 }
 
-fn new_etcd_client(core: &Core) -> Result<etcd::Client<hyper::client::HttpConnector>> {
+// Build the BasicAuth credentials to send to etcd, if both a username and
+// password were configured. A production etcd with authentication enabled
+// rejects anonymous requests, so this lets simple-secrets run against one.
+fn etcd_basic_auth() -> Option<etcd::BasicAuth> {
+    match (ETCD_USERNAME.clone(), ETCD_PASSWORD.clone()) {
+        (Some(username), Some(password)) => Some(etcd::BasicAuth { username, password }),
+        _ => None,
+    }
+}
+
+// Construct the TLS connector used for https:// members. The system trust
+// store is used by default; ETCD_CA_CERT adds a private CA and ETCD_CLIENT_CERT
+// (a PKCS#12 bundle) enables mutual TLS for clusters that require client certs.
+fn https_connector(core: &Core) -> Result<HttpsConnector<hyper::client::HttpConnector>> {
+    let mut builder = native_tls::TlsConnector::builder();
+
+    if let Some(ref path) = *ETCD_CA_CERT {
+        let mut pem = Vec::new();
+        std::fs::File::open(path)?.read_to_end(&mut pem)?;
+        let ca = native_tls::Certificate::from_pem(&pem)
+            .chain_err(|| "Unable to parse ETCD_CA_CERT")?;
+        builder.add_root_certificate(ca);
+    }
+
+    if let Some(ref path) = *ETCD_CLIENT_CERT {
+        let mut der = Vec::new();
+        std::fs::File::open(path)?.read_to_end(&mut der)?;
+        let identity = native_tls::Identity::from_pkcs12(&der, &ETCD_CLIENT_KEY_PASSWORD)
+            .chain_err(|| "Unable to parse ETCD_CLIENT_CERT")?;
+        builder.identity(identity);
+    }
+
+    let tls = builder.build().chain_err(|| "Unable to build TLS connector")?;
+
+    let mut http = hyper::client::HttpConnector::new(4, &core.handle());
+    http.enforce_http(false);
+    Ok(HttpsConnector::from((http, tls)))
+}
+
+fn new_etcd_client(core: &Core) -> Result<etcd::Client<HttpsConnector<hyper::client::HttpConnector>>> {
     let handle = core.handle();
-    etcd::Client::new(&handle,ETCD_CLUSTER_MEMBERS.split(",").collect::<Vec<&str>>().as_slice(), None).chain_err(|| "Cannot create etcd client")
+    let members = ETCD_CLUSTER_MEMBERS.split(",").collect::<Vec<&str>>();
+    let connector = https_connector(core)?;
+    etcd::Client::https(&handle, members.as_slice(), connector, etcd_basic_auth())
+        .chain_err(|| "Cannot create etcd client")
 }
 
 type AuthToken = String;
@@ -111,13 +318,30 @@ struct UserInfo {
     token: AuthToken,
 }
 
-fn fetch_user_password(user_info: &mut UserInfo) {  
-    if let Ok(value) = get_etcd_key(&format!("/users/{}/password", user_info.username)) {
+// Claims carried by a signed session JWT. `sub` is the authenticated username;
+// `iat`/`exp` bound the token's validity window in seconds since the epoch.
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    iat: u64,
+    exp: u64,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn fetch_user_password(user_info: &mut UserInfo) {
+    if let Ok(value) = store_get(&format!("/users/{}/password", user_info.username)) {
         user_info.encoded_password = value
     }
 }
 
 fn verify_password(user_info: &UserInfo) -> bool {
+    let _span = tracer().start("argon2_verify");
     let mut verifier = argonautica::Verifier::default();
     if let Ok(true) = verifier
         .with_hash(&user_info.encoded_password)
@@ -143,7 +367,13 @@ fn login(req: &mut Request) -> IronResult<Response> {
         Some(password) => password,
         None  => return Ok(Response::with(iron::status::Unauthorized))
     };
-    
+
+    // Open a request span (continuing any upstream trace) so the etcd and
+    // argon2 work below nests beneath it.
+    let mut span = tracer().start_with_context("login", &parent_context(req));
+    span.set_attribute(KeyValue::new("user", user_info.username.clone()));
+    let _guard = mark_span_as_active(span);
+
     // Fetch user password from etcd
     fetch_user_password(&mut user_info);
 
@@ -154,6 +384,49 @@ fn login(req: &mut Request) -> IronResult<Response> {
         return Ok(Response::with(iron::status::Unauthorized))
     }
 
+    // If the user has enrolled a TOTP secret, require a valid second factor
+    // supplied in the X-Secret-OTP header or an otp= query parameter.
+    if let Ok(totp_secret) = store_get(&format!("/users/{}/totp", user_info.username)) {
+        if !totp_secret.is_empty() {
+            let code = req
+                .headers
+                .get_raw("X-Secret-OTP")
+                .and_then(|values| values.get(0))
+                .and_then(|raw| std::str::from_utf8(raw).ok())
+                .map(String::from)
+                .or_else(|| req.url.query().and_then(|query| {
+                    query.split('&').find_map(|pair| {
+                        let mut parts = pair.splitn(2, '=');
+                        match (parts.next(), parts.next()) {
+                            (Some("otp"), Some(value)) => Some(value.to_string()),
+                            _ => None,
+                        }
+                    })
+                }));
+
+            if !code.map(|code| verify_totp(&totp_secret, &code)).unwrap_or(false) {
+                audit_event("LOGIN_FAILURE_INVALID_TOTP", &format!("Login failure for user {} due to missing or invalid TOTP code", user_info.username));
+                return Ok(Response::with(iron::status::Unauthorized))
+            }
+        }
+    }
+
+    // In JWT mode the token is stateless and self-validating, so there is no
+    // etcd write; otherwise fall back to minting and storing an opaque token.
+    if *JWT_SESSION_TOKENS {
+        user_info.token = match generate_jwt(&user_info.username) {
+            Ok(token) => token,
+            Err(e) => {
+                eprintln!("Unable to sign session token: {}", e);
+                audit_event("LOGIN_FAILURE_TOKEN_CREATION_FAIL", &format!("Login failure for user {} due to token creation failure", user_info.username));
+                return Ok(Response::with(iron::status::InternalServerError));
+            }
+        };
+        audit_event("TOKEN_CREATED", &format!("Session token for user {} created", user_info.username));
+        audit_event("LOGIN_SUCCESS", &format!("Login success for user {}", user_info.username));
+        return Ok(Response::with((iron::status::Ok, user_info.token)));
+    }
+
     // Generate and set new token
     user_info.token = generate_authorization_token();
     if let Ok(_) = update_user_token(&user_info) {
@@ -166,6 +439,21 @@ fn login(req: &mut Request) -> IronResult<Response> {
     }
 }
 
+// Mint a signed HS256 JWT for `username`, carrying the issue time and an
+// expiry TOKEN_EXPIRATION_SECS in the future. The token is self-validating, so
+// no etcd round trip is needed on subsequent requests.
+fn generate_jwt(username: &str) -> Result<String> {
+    let iat = unix_now();
+    let claims = Claims {
+        sub: username.to_string(),
+        iat,
+        exp: iat + *TOKEN_EXPIRATION_SECS,
+    };
+
+    encode(&Header::new(Algorithm::HS256), &claims, JWT_SECRET.as_ref())
+        .chain_err(|| "Unable to sign session token")
+}
+
 fn generate_authorization_token() -> String {
     let mut rng = thread_rng();
     std::iter::repeat(())
@@ -175,11 +463,142 @@ fn generate_authorization_token() -> String {
 }
 
 fn update_user_token(user_info: &UserInfo) -> Result<()> { 
-    set_etcd_key(&format!("/session_tokens/{}", user_info.token), &user_info.username, Some(*TOKEN_EXPIRATION_SECS))?;
+    store_set(&format!("/session_tokens/{}", user_info.token), &user_info.username, Some(*TOKEN_EXPIRATION_SECS))?;
     
     Ok(())
 }
 
+// A single RBAC grant: read and/or write access to every secret whose path
+// begins with `prefix`. Roles stored at `/roles/{role}` are JSON arrays of
+// these, modeled on etcd's own key-prefix permission design.
+#[derive(Debug, Serialize, Deserialize)]
+struct Grant {
+    prefix: String,
+    #[serde(default)]
+    read: bool,
+    #[serde(default)]
+    write: bool,
+}
+
+// Resolve a user's effective grants by loading its role bindings from
+// `/users/{user}/roles` and expanding each role's grant list.
+fn effective_grants(username: &str) -> Vec<Grant> {
+    let roles = match store_get(&format!("/users/{}/roles", username)) {
+        Ok(ref raw) if !raw.is_empty() => {
+            serde_json::from_str::<Vec<String>>(raw).unwrap_or_default()
+        }
+        _ => Vec::new(),
+    };
+
+    let mut grants = Vec::new();
+    for role in roles {
+        if let Ok(raw) = store_get(&format!("/roles/{}", role)) {
+            if let Ok(mut role_grants) = serde_json::from_str::<Vec<Grant>>(&raw) {
+                grants.append(&mut role_grants);
+            }
+        }
+    }
+    grants
+}
+
+// Authorize an operation against a secret's path. Fails closed: any missing
+// binding, malformed role, or etcd error results in denial.
+fn authorize_secret(username: &str, resource: &str, write: bool) -> bool {
+    effective_grants(username).iter().any(|grant| {
+        resource.starts_with(&grant.prefix) && if write { grant.write } else { grant.read }
+    })
+}
+
+// An administrator is any user bound to the reserved `admin` role. Only admins
+// may create roles or change user bindings.
+fn is_admin(username: &str) -> bool {
+    match store_get(&format!("/users/{}/roles", username)) {
+        Ok(ref raw) if !raw.is_empty() => serde_json::from_str::<Vec<String>>(raw)
+            .map(|roles| roles.iter().any(|role| role == "admin"))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn read_body(req: &mut Request) -> Result<String> {
+    let mut body = String::new();
+    req.body.read_to_string(&mut body).chain_err(|| "Unable to read request body")?;
+    Ok(body)
+}
+
+// Resolve and authorize the bearer of the request's `token=` query parameter as
+// an administrator, used to guard the role-management endpoints.
+fn authorize_admin(req: &mut Request) -> std::result::Result<String, Response> {
+    let token = match req.url.query() {
+        Some(val) => val.replace("token=", ""),
+        None => return Err(Response::with((iron::status::BadRequest, "Token required"))),
+    };
+    let username = match validate_token(&token) {
+        Ok(username) => username,
+        Err(_) => return Err(Response::with((iron::status::Unauthorized, "Bad token"))),
+    };
+    if !is_admin(&username) {
+        return Err(Response::with((iron::status::Forbidden, "Access denied")));
+    }
+    Ok(username)
+}
+
+// Create or replace a role's grant list. Body is a JSON array of `Grant`.
+fn create_role(req: &mut Request) -> IronResult<Response> {
+    if let Err(response) = authorize_admin(req) {
+        return Ok(response);
+    }
+
+    let name = match req.extensions.get::<Router>() {
+        Some(params) => params.find("name").unwrap_or("").to_string(),
+        None => return Ok(Response::with(iron::status::BadRequest)),
+    };
+
+    let body = match read_body(req) {
+        Ok(body) => body,
+        Err(_) => return Ok(Response::with(iron::status::BadRequest)),
+    };
+    if serde_json::from_str::<Vec<Grant>>(&body).is_err() {
+        return Ok(Response::with((iron::status::BadRequest, "Invalid grant list")));
+    }
+
+    match store_set(&format!("/roles/{}", name), &body, None) {
+        Ok(_) => Ok(Response::with(iron::status::Ok)),
+        Err(e) => {
+            eprintln!("Unable to store role {}: {}", name, e);
+            Ok(Response::with(iron::status::InternalServerError))
+        }
+    }
+}
+
+// Bind a user to a set of roles. Body is a JSON array of role names.
+fn set_user_roles(req: &mut Request) -> IronResult<Response> {
+    if let Err(response) = authorize_admin(req) {
+        return Ok(response);
+    }
+
+    let name = match req.extensions.get::<Router>() {
+        Some(params) => params.find("name").unwrap_or("").to_string(),
+        None => return Ok(Response::with(iron::status::BadRequest)),
+    };
+
+    let body = match read_body(req) {
+        Ok(body) => body,
+        Err(_) => return Ok(Response::with(iron::status::BadRequest)),
+    };
+    if serde_json::from_str::<Vec<String>>(&body).is_err() {
+        return Ok(Response::with((iron::status::BadRequest, "Invalid role list")));
+    }
+
+    match store_set(&format!("/users/{}/roles", name), &body, None) {
+        Ok(_) => Ok(Response::with(iron::status::Ok)),
+        Err(e) => {
+            eprintln!("Unable to store roles for user {}: {}", name, e);
+            Ok(Response::with(iron::status::InternalServerError))
+        }
+    }
+}
+
 fn set_secret(req: &mut Request) -> IronResult<Response> {
     // Parse name/value from URL
     let args;
@@ -189,7 +608,13 @@ fn set_secret(req: &mut Request) -> IronResult<Response> {
         Some(params) => args = (params.find("name").unwrap_or(""), params.find("value").unwrap_or("")),
         None => return Ok(Response::with(iron::status::BadRequest))
     };
-    
+
+    // Open a request span (continuing any upstream trace) around the token
+    // validation and etcd writes below.
+    let mut span = tracer().start_with_context("set_secret", &parent_context(req));
+    span.set_attribute(KeyValue::new("secret", args.0.to_string()));
+    let _guard = mark_span_as_active(span);
+
     // Validate token
     let token;
     if let Some(val) = req.url.query() {
@@ -207,15 +632,31 @@ fn set_secret(req: &mut Request) -> IronResult<Response> {
         return Ok(Response::with((iron::status::Unauthorized, "Bad token")));
     }
 
+    // Enforce RBAC before touching etcd
+    let resource = format!("/secrets/{}", args.0);
+    if !authorize_secret(&username, &resource, true) {
+        audit_event("SECRET_ACCESS_DENIED", &format!("User {} denied write access to secret {}", username, args.0));
+        return Ok(Response::with((iron::status::Forbidden, "Access denied")));
+    }
+
     // Set secret
     let uuid = uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_DNS, args.0.as_bytes()); // Use secret name to gen SHA1-based UUID
-    if let Err(e) = set_etcd_key(&format!("/secrets/{}/name", uuid), args.0, None) {
+    if let Err(e) = store_set(&format!("/secrets/{}/name", uuid), args.0, None) {
         eprintln!("Unable to set secret key: {}", e);
         audit_event("SECRET_CREATE_FAILURE", &format!("Unable to set secret {} by user {}, internal error", args.0, username));
 
         return Ok(Response::with(iron::status::InternalServerError));
     }
-    if let Err(e) = set_etcd_key(&format!("/secrets/{}/value", uuid), args.1, None) {
+    let sealed = match seal_secret(args.1) {
+        Ok(sealed) => sealed,
+        Err(e) => {
+            eprintln!("Unable to seal secret value: {}", e);
+            audit_event("SECRET_CREATE_FAILURE", &format!("Unable to set secret {} by user {}, internal error", args.0, username));
+
+            return Ok(Response::with(iron::status::InternalServerError));
+        }
+    };
+    if let Err(e) = store_set(&format!("/secrets/{}/value", uuid), &sealed, None) {
         eprintln!("Unable to set secret value: {}", e);
         audit_event("SECRET_CREATE_FAILURE", &format!("Unable to set secret {} by user {}, internal error", args.0, username));
 
@@ -227,57 +668,419 @@ fn set_secret(req: &mut Request) -> IronResult<Response> {
     Ok(Response::with((iron::status::Ok, format!("{}", uuid))))
 }
 
-fn set_etcd_key(key: &str, value: &str, expiration: Option<u64>) -> Result<()> {
-    let mut core = Core::new()?;
-    let client = match new_etcd_client(&core) {
-        Ok(client) => client,
-        Err(_) => Err("Unable to create etcd client")?
-    };
+// Seal a secret value for storage at rest. A fresh 24-byte nonce is generated
+// per call and prepended to the XSalsa20-Poly1305 ciphertext; the result is
+// base64-encoded for storage in etcd.
+fn seal_secret(plaintext: &str) -> Result<String> {
+    let mut nonce_bytes = [0u8; secretbox::NONCEBYTES];
+    thread_rng().fill(&mut nonce_bytes[..]);
+    let nonce = secretbox::Nonce::from_slice(&nonce_bytes)
+        .ok_or("Unable to construct secretbox nonce")?;
+    let ciphertext = secretbox::seal(plaintext.as_bytes(), &nonce, &MASTER_KEY);
 
-    let set_token = kv::set(&client, key, value, expiration);
-    core.run(set_token).or(Err(format!("Unable to update etcd key {}", key)))?;
+    let mut blob = Vec::with_capacity(nonce_bytes.len() + ciphertext.len());
+    blob.extend_from_slice(&nonce_bytes);
+    blob.extend_from_slice(&ciphertext);
+    Ok(base64::encode(&blob))
+}
 
-    Ok(())
+// Open a sealed secret produced by seal_secret, returning a generic error on
+// any failure so a decode, length, or tag-check failure is indistinguishable
+// from a plain lookup miss.
+fn open_secret(sealed: &str) -> Result<String> {
+    let blob = base64::decode(sealed).or(Err("Unable to open secret"))?;
+    if blob.len() < secretbox::NONCEBYTES {
+        Err("Unable to open secret")?;
+    }
+
+    let (nonce_bytes, ciphertext) = blob.split_at(secretbox::NONCEBYTES);
+    let nonce = secretbox::Nonce::from_slice(nonce_bytes).ok_or("Unable to open secret")?;
+    let plaintext = secretbox::open(ciphertext, &nonce, &MASTER_KEY).or(Err("Unable to open secret"))?;
+
+    String::from_utf8(plaintext).map_err(|_| "Unable to open secret".into())
+}
+
+// Compare two strings in constant time so a partial match is not distinguishable
+// by how long the comparison takes.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
 }
 
-fn get_etcd_key(key: &str) -> Result<String> {
-    let mut core = Core::new()?;
-    let client = match new_etcd_client(&core) {
-        Ok(client) => client,
-        Err(_) => Err("Unable to create etcd client")?
+type HmacSha1 = Hmac<Sha1>;
+
+// Compute the 6-digit HOTP value for a shared secret and counter per RFC 4226
+// (the building block of TOTP): HMAC-SHA1 over the big-endian counter, dynamic
+// truncation, modulo 10^6.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = HmacSha1::new_varkey(secret).expect("HMAC accepts keys of any size");
+    mac.input(&counter.to_be_bytes());
+    let code = mac.result().code();
+
+    let offset = (code[code.len() - 1] & 0x0f) as usize;
+    let binary = ((u32::from(code[offset]) & 0x7f) << 24)
+        | (u32::from(code[offset + 1]) << 16)
+        | (u32::from(code[offset + 2]) << 8)
+        | u32::from(code[offset + 3]);
+
+    binary % 1_000_000
+}
+
+// Verify a 6-digit TOTP code (RFC 6238) against a base32 secret, tolerating
+// ±1 time step of clock skew around the current 30-second window.
+fn verify_totp(secret_b32: &str, code: &str) -> bool {
+    let secret = match data_encoding::BASE32_NOPAD.decode(secret_b32.as_bytes()) {
+        Ok(secret) => secret,
+        Err(_) => return false,
+    };
+    let expected: u32 = match code.trim().parse() {
+        Ok(value) => value,
+        Err(_) => return false,
     };
 
-    let mut value = None;
-    {
-        let get_token = kv::get(&client, key, kv::GetOptions::default()).and_then(|response| {
-            value = response.data.node.value;
+    let step = unix_now() / 30;
+    (-1i64..=1).any(|skew| {
+        let counter = (step as i64 + skew) as u64;
+        constant_time_eq(&format!("{:06}", hotp(&secret, counter)), &format!("{:06}", expected))
+    })
+}
 
-            Ok(())
-        });
-    core.run(get_token).or(Err(format!("Unable to fetch etcd key {}", key)))?;
+// Persistence abstraction over the key/value store holding secrets, roles and
+// user bindings. Backends are selected at startup by STORAGE_BACKEND so a
+// deployment without an etcd cluster can still run the server. TTL support is
+// threaded through `set` for entries (such as session tokens) that should
+// expire on their own. `get` reports a missing (or expired) key as an `Err`
+// regardless of backend, so handlers need not special-case which store is in
+// use.
+trait SecretStore: Send + Sync {
+    fn get(&self, key: &str) -> Result<String>;
+    fn set(&self, key: &str, value: &str, expiration: Option<u64>) -> Result<()>;
+    fn delete(&self, key: &str) -> Result<()>;
+}
+
+// etcd-backed store. Each call opens a short-lived reactor and client, matching
+// how the rest of the server talks to etcd.
+struct EtcdStore;
+
+impl SecretStore for EtcdStore {
+    fn get(&self, key: &str) -> Result<String> {
+        let mut core = Core::new()?;
+        let client = match new_etcd_client(&core) {
+            Ok(client) => client,
+            Err(_) => Err("Unable to create etcd client")?
+        };
+
+        let mut value = None;
+        {
+            let get_token = kv::get(&client, key, kv::GetOptions::default()).and_then(|response| {
+                value = response.data.node.value;
+
+                Ok(())
+            });
+            core.run(get_token).or(Err(format!("Unable to fetch etcd key {}", key)))?;
+        }
+
+        Ok(value.unwrap_or(String::from("")))
     }
 
-    Ok(value.unwrap_or(String::from("")))
+    fn set(&self, key: &str, value: &str, expiration: Option<u64>) -> Result<()> {
+        let mut core = Core::new()?;
+        let client = match new_etcd_client(&core) {
+            Ok(client) => client,
+            Err(_) => Err("Unable to create etcd client")?
+        };
+
+        let set_token = kv::set(&client, key, value, expiration);
+        core.run(set_token).or(Err(format!("Unable to update etcd key {}", key)))?;
+
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let mut core = Core::new()?;
+        let client = match new_etcd_client(&core) {
+            Ok(client) => client,
+            Err(_) => Err("Unable to create etcd client")?
+        };
+
+        let delete_token = kv::delete(&client, key, false);
+        core.run(delete_token).or(Err(format!("Unable to delete etcd key {}", key)))?;
+
+        Ok(())
+    }
+}
+
+// SQLite-backed store for deployments without etcd. Keys and values live in a
+// single table; an optional absolute expiry implements the TTL contract, with
+// expired rows treated as absent.
+struct SqliteStore {
+    connection: std::sync::Mutex<rusqlite::Connection>,
+}
+
+impl SqliteStore {
+    fn open(path: &str) -> Result<SqliteStore> {
+        let connection = rusqlite::Connection::open(path)
+            .chain_err(|| "Unable to open sqlite database")?;
+        connection
+            .execute(
+                "CREATE TABLE IF NOT EXISTS kv (
+                    key TEXT PRIMARY KEY,
+                    value TEXT NOT NULL,
+                    expires_at INTEGER
+                )",
+                rusqlite::NO_PARAMS,
+            )
+            .chain_err(|| "Unable to initialize sqlite schema")?;
+        Ok(SqliteStore {
+            connection: std::sync::Mutex::new(connection),
+        })
+    }
+}
+
+impl SecretStore for SqliteStore {
+    fn get(&self, key: &str) -> Result<String> {
+        let connection = self.connection.lock().expect("sqlite lock poisoned");
+        let now = unix_now() as i64;
+        let value: std::result::Result<String, rusqlite::Error> = connection.query_row(
+            "SELECT value FROM kv WHERE key = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+            &[&key as &dyn rusqlite::types::ToSql, &now],
+            |row| row.get(0),
+        );
+        match value {
+            Ok(value) => Ok(value),
+            // Mirror the etcd backend, which errors on a missing key, so a
+            // nonexistent secret is reported uniformly rather than as Ok("").
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                Err(format!("Unable to fetch sqlite key {}", key).into())
+            }
+            Err(e) => Err(e).chain_err(|| format!("Unable to fetch sqlite key {}", key)),
+        }
+    }
+
+    fn set(&self, key: &str, value: &str, expiration: Option<u64>) -> Result<()> {
+        let connection = self.connection.lock().expect("sqlite lock poisoned");
+        let expires_at = expiration.map(|ttl| (unix_now() + ttl) as i64);
+        connection
+            .execute(
+                "INSERT OR REPLACE INTO kv (key, value, expires_at) VALUES (?1, ?2, ?3)",
+                &[&key as &dyn rusqlite::types::ToSql, &value, &expires_at],
+            )
+            .chain_err(|| format!("Unable to update sqlite key {}", key))?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let connection = self.connection.lock().expect("sqlite lock poisoned");
+        connection
+            .execute(
+                "DELETE FROM kv WHERE key = ?1",
+                &[&key as &dyn rusqlite::types::ToSql],
+            )
+            .chain_err(|| format!("Unable to delete sqlite key {}", key))?;
+        Ok(())
+    }
+}
+
+// Route a read through the configured backend.
+fn store_get(key: &str) -> Result<String> {
+    let _span = tracer().start("get_etcd_key");
+    STORE.get(key)
+}
+
+// Route a write through the configured backend.
+fn store_set(key: &str, value: &str, expiration: Option<u64>) -> Result<()> {
+    let _span = tracer().start("set_etcd_key");
+    STORE.set(key, value, expiration)
 }
 
 fn validate_token(token: &str) -> Result<String> {
-    let mut core = Core::new()?;
-    let client = match new_etcd_client(&core) {
-        Ok(client) => client,
-        Err(_) => Err("Unable to create etcd client")?
-    };
+    let _span = tracer().start("validate_token");
+    // In JWT mode the signature and expiry are verified locally with no etcd
+    // lookup; the opaque-token path below is used otherwise.
+    if *JWT_SESSION_TOKENS {
+        return validate_jwt(token);
+    }
 
-    let mut username = None;
-    {
-    let fetch_token = kv::get(&client, &format!("/session_tokens/{}", token), kv::GetOptions::default()).and_then(|response| {
-        username = response.data.node.value;
+    // Opaque tokens are looked up through the configured backend, so a
+    // deployment without etcd reads them from the same store login wrote them
+    // to. A missing key surfaces as an error; an empty value is treated the
+    // same way so a tombstoned token cannot authenticate.
+    match store_get(&format!("/session_tokens/{}", token)) {
+        Ok(ref username) if !username.is_empty() => Ok(username.clone()),
+        _ => Err(format!("Token {} not found", token).into()),
+    }
+}
+
+// Verify a session JWT's signature and expiry locally, returning its subject.
+// The `exp` claim is checked automatically by the validator.
+fn validate_jwt(token: &str) -> Result<String> {
+    let validation = Validation::new(Algorithm::HS256);
+    let data = decode::<Claims>(token, JWT_SECRET.as_ref(), &validation)
+        .chain_err(|| format!("Token {} not valid", token))?;
+
+    Ok(data.claims.sub)
+}
 
+// Streaming response body for a watch subscription. Each change pushed through
+// the channel is written to the client as a newline-terminated event and
+// flushed immediately; the response stays open until the subscriber's sender is
+// dropped (the per-UUID watch thread exited) or the connection is torn down.
+struct SubscriberBody {
+    rx: Receiver<String>,
+}
+
+impl iron::response::WriteBody for SubscriberBody {
+    fn write_body(&mut self, body: &mut iron::response::ResponseBody) -> std::io::Result<()> {
+        while let Ok(event) = self.rx.recv() {
+            body.write_all(event.as_bytes())?;
+            body.write_all(b"\n")?;
+            body.flush()?;
+        }
         Ok(())
+    }
+}
+
+// Fan a value change out to every subscriber registered for `uuid`, dropping
+// any whose receiving end has gone away. Returns the number of live
+// subscribers remaining so the watch thread can retire itself when none are.
+fn fanout_watch_event(uuid: &str, value: &str) -> usize {
+    let mut registry = WATCH_REGISTRY.write().expect("watch registry lock poisoned");
+    if let Some(senders) = registry.get_mut(uuid) {
+        senders.retain(|sender| sender.send(value.to_string()).is_ok());
+        let remaining = senders.len();
+        if remaining == 0 {
+            registry.remove(uuid);
+        }
+        remaining
+    } else {
+        0
+    }
+}
+
+// Ensure a single etcd watch thread is running for `uuid`. The caller has
+// already registered its subscriber; this spawns the fan-out thread only when
+// it created the first subscription for the UUID. The thread watches
+// `/secrets/{uuid}/value`, re-establishing the watch if the stream ends, and
+// exits once the last subscriber has disconnected.
+fn ensure_watcher(uuid: String) {
+    let key = format!("/secrets/{}/value", uuid);
+    std::thread::spawn(move || loop {
+        let mut core = match Core::new() {
+            Ok(core) => core,
+            Err(e) => {
+                eprintln!("Unable to create watch reactor for {}: {}", uuid, e);
+                return;
+            }
+        };
+        let client = match new_etcd_client(&core) {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("Unable to create watch client for {}: {}", uuid, e);
+                return;
+            }
+        };
+
+        let options = kv::WatchOptions {
+            recursive: false,
+            ..Default::default()
+        };
+        let watch = kv::watch(&client, &key, options).for_each(|response| {
+            if let Some(value) = response.data.node.value {
+                audit_event(
+                    "SECRET_WATCH_EVENT",
+                    &format!("Change pushed to subscribers of secret UUID {}", uuid),
+                );
+                if fanout_watch_event(&uuid, &value) == 0 {
+                    // Last subscriber gone; unwind the stream so the thread exits.
+                    return Err(etcd::Error::Timeout);
+                }
+            }
+            Ok(())
+        });
+
+        if core.run(watch).is_err() {
+            // Either the last subscriber left or the watch failed; retire the
+            // thread if no subscribers remain, otherwise re-establish.
+            let alive = WATCH_REGISTRY
+                .read()
+                .expect("watch registry lock poisoned")
+                .get(&uuid)
+                .map(|senders| !senders.is_empty())
+                .unwrap_or(false);
+            if !alive {
+                return;
+            }
+        }
     });
-    core.run(fetch_token).or(Err(format!("Token {} not found", token)))?;
+}
+
+// The watch subsystem is built on etcd's native change stream, which the
+// SecretStore abstraction does not expose. On a non-etcd backend there is
+// nothing to watch, so the endpoint is rejected rather than silently failing to
+// open an etcd client that is not configured.
+fn watch_secret(req: &mut Request) -> IronResult<Response> {
+    // Parse name from URL
+    let name;
+
+    match req.extensions.get::<Router>() {
+        Some(params) => name = params.find("name").unwrap_or(""),
+        None => return Ok(Response::with(iron::status::BadRequest)),
+    };
+
+    if STORAGE_BACKEND.as_str() != "etcd" {
+        audit_event(
+            "SECRET_WATCH_UNSUPPORTED_BACKEND",
+            &format!("Secret {} watch rejected, backend {} has no watch support", name, *STORAGE_BACKEND),
+        );
+        return Ok(Response::with((
+            iron::status::NotImplemented,
+            "Watch requires the etcd storage backend",
+        )));
     }
-    
-    Ok(username.unwrap_or(String::from("")))
+
+    // Validate token
+    let token;
+    if let Some(val) = req.url.query() {
+        token = val.replace("token=", "");
+    } else {
+        audit_event("SECRET_WATCH_FAILURE_NO_TOKEN", &format!("Secret {} failed watch, no token entered attempt", name));
+        return Ok(Response::with((iron::status::BadRequest, "Token required")));
+    }
+
+    let username;
+    if let Ok(val) = validate_token(&token) {
+        username = val;
+    } else {
+        audit_event("SECRET_WATCH_FAILURE_INVALID_TOKEN", &format!("Secret {} failed watch, invalid token attempt", name));
+        return Ok(Response::with((iron::status::Unauthorized, "Bad token")));
+    }
+
+    // Enforce RBAC before subscribing; watching a value leaks its changes.
+    let resource = format!("/secrets/{}", name);
+    if !authorize_secret(&username, &resource, false) {
+        audit_event("SECRET_ACCESS_DENIED", &format!("User {} denied watch access to secret {}", username, name));
+        return Ok(Response::with((iron::status::Forbidden, "Access denied")));
+    }
+
+    // Register this connection as a subscriber, spawning the per-UUID watch
+    // thread if it is the first subscriber for the secret.
+    let uuid = uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_DNS, name.as_bytes()).to_string();
+    let (tx, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+    let spawn_watcher = {
+        let mut registry = WATCH_REGISTRY.write().expect("watch registry lock poisoned");
+        let senders = registry.entry(uuid.clone()).or_insert_with(Vec::new);
+        let first = senders.is_empty();
+        senders.push(tx);
+        first
+    };
+    if spawn_watcher {
+        ensure_watcher(uuid.clone());
+    }
+
+    audit_event("SECRET_WATCH_SUBSCRIBE", &format!("Secret {} UUID {} watched by user {}", name, uuid, username));
+
+    let mut response = Response::with(iron::status::Ok);
+    response.body = Some(Box::new(SubscriberBody { rx }));
+    Ok(response)
 }
 
 fn fetch_secret(req: &mut Request) -> IronResult<Response> {
@@ -289,7 +1092,13 @@ fn fetch_secret(req: &mut Request) -> IronResult<Response> {
         Some(params) => name = params.find("name").unwrap_or(""),
         None => return Ok(Response::with(iron::status::BadRequest)) // This should never happen
     };
-    
+
+    // Open a request span (continuing any upstream trace) around the token
+    // validation and etcd read below.
+    let mut span = tracer().start_with_context("fetch_secret", &parent_context(req));
+    span.set_attribute(KeyValue::new("secret", name.to_string()));
+    let _guard = mark_span_as_active(span);
+
     // Validate token
     let token;
     if let Some(val) = req.url.query() {
@@ -307,14 +1116,28 @@ fn fetch_secret(req: &mut Request) -> IronResult<Response> {
         return Ok(Response::with((iron::status::Unauthorized, "Bad token")));
     }
 
+    // Enforce RBAC before touching etcd
+    let resource = format!("/secrets/{}", name);
+    if !authorize_secret(&username, &resource, false) {
+        audit_event("SECRET_ACCESS_DENIED", &format!("User {} denied read access to secret {}", username, name));
+        return Ok(Response::with((iron::status::Forbidden, "Access denied")));
+    }
+
     // Fetch secret
     let uuid = uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_DNS, name.as_bytes());
-    let value = get_etcd_key(&format!("/secrets/{}/value", uuid));
+    let value = store_get(&format!("/secrets/{}/value", uuid));
     match value {
-        Ok(value) => {
-            audit_event("SECRET_FETCH_SUCCESS", &format!("Secret {} UUID {} fetched by user {}", name, uuid, username));
-            Ok(Response::with((iron::status::Ok, value)))
-        }, 
+        Ok(sealed) => match open_secret(&sealed) {
+            Ok(value) => {
+                audit_event("SECRET_FETCH_SUCCESS", &format!("Secret {} UUID {} fetched by user {}", name, uuid, username));
+                Ok(Response::with((iron::status::Ok, value)))
+            },
+            Err(e) => {
+                eprintln!("Unable to open secret: {}", e);
+                audit_event("SECRET_DECRYPT_FAILURE", &format!("Secret {} UUID {} failed to decrypt for user {}, possible tampering", name, uuid, username));
+                Ok(Response::with((iron::status::BadRequest, "Invalid secret")))
+            }
+        },
         Err(e) => {
             eprintln!("Unable to fetch secret: {}", e);
             audit_event("SECRET_FETCH_FAILURE_NOEXIST", &format!("Secret {} failed fetch by user {}, does not exist", name, username));